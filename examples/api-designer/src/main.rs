@@ -8,6 +8,7 @@ Tech: Rust 2021, Actix-Web, Claude 3.5 Sonnet
 
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Mutex;
 
 #[derive(Serialize, Deserialize)]
@@ -15,26 +16,376 @@ struct APIDesignRequest {
     service_name: String,
     endpoints: Vec<EndpointSpec>,
     auth_type: String,
+    /// Artifacts to produce; see `OutputFormat`. Only `openapi` when empty.
+    #[serde(default)]
+    output_formats: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct EndpointSpec {
     path: String,
-    method: String,
+    method: HttpMethod,
     description: String,
 }
 
+/// An HTTP method, parsed case-insensitively and written uppercase.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum HttpMethod {
+    Get,
+    Post,
+    Put,
+    Patch,
+    Delete,
+    Head,
+    Options,
+    Trace,
+}
+
+impl HttpMethod {
+    /// In the order operations are listed within a path item.
+    const ALL: [HttpMethod; 8] = [
+        HttpMethod::Get,
+        HttpMethod::Put,
+        HttpMethod::Post,
+        HttpMethod::Delete,
+        HttpMethod::Options,
+        HttpMethod::Head,
+        HttpMethod::Patch,
+        HttpMethod::Trace,
+    ];
+
+    /// The lowercase name OpenAPI uses as the operation's key in a path item.
+    fn key(self) -> &'static str {
+        match self {
+            HttpMethod::Get => "get",
+            HttpMethod::Post => "post",
+            HttpMethod::Put => "put",
+            HttpMethod::Patch => "patch",
+            HttpMethod::Delete => "delete",
+            HttpMethod::Head => "head",
+            HttpMethod::Options => "options",
+            HttpMethod::Trace => "trace",
+        }
+    }
+}
+
+impl std::str::FromStr for HttpMethod {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        HttpMethod::ALL
+            .into_iter()
+            .find(|method| method.key().eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                format!(
+                    "unknown HTTP method '{}'; expected one of GET, POST, PUT, PATCH, DELETE, HEAD, OPTIONS, TRACE",
+                    name
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for HttpMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.key().to_uppercase())
+    }
+}
+
+impl Serialize for HttpMethod {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HttpMethod {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Serialize)]
 struct APIDesignResponse {
     openapi_spec: String,
+    /// Each requested format's artifact, keyed by format name.
+    artifacts: BTreeMap<String, String>,
     best_practices: Vec<String>,
     security_recommendations: Vec<String>,
 }
 
+#[derive(Deserialize)]
+struct SpecUpdateRequest {
+    /// The current specification, as returned in `openapi_spec`.
+    spec: String,
+    /// One change per line (or separated by `;`), see `apply_change`.
+    change_description: String,
+}
+
+#[derive(Serialize)]
+struct SpecUpdateResponse {
+    /// RFC 6902 operations that turn `spec` into `updated_spec`.
+    patch: json_patch::Patch,
+    updated_spec: String,
+}
+
+#[derive(Deserialize)]
+struct ScaffoldRequest {
+    /// Specification to scaffold, as returned in `openapi_spec`.
+    spec: String,
+    /// Target language; see `ScaffoldLanguage` for the supported ones.
+    language: String,
+}
+
+#[derive(Serialize)]
+struct ScaffoldHandler {
+    method: HttpMethod,
+    path: String,
+    name: String,
+}
+
+#[derive(Serialize)]
+struct ScaffoldResponse {
+    language: String,
+    framework: String,
+    handlers: Vec<ScaffoldHandler>,
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct ContractTestRequest {
+    /// Specification to test against, as returned in `openapi_spec`.
+    spec: String,
+    /// Test framework; see `ContractFramework` for the supported ones.
+    framework: String,
+}
+
+/// One request/expected-response pair derived from an operation.
+#[derive(Serialize)]
+struct ContractCase {
+    name: String,
+    method: HttpMethod,
+    /// The spec path, e.g. `/users/{id}`.
+    path: String,
+    /// `path` with parameters filled from their examples, e.g. `/users/1`.
+    request_path: String,
+    request_body: Option<serde_json::Value>,
+    expected_status: u16,
+    /// Top-level fields the response body must contain, from the schema's `required`.
+    expected_fields: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ContractTestResponse {
+    framework: String,
+    cases: Vec<ContractCase>,
+    code: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContractFramework {
+    Pytest,
+    Jest,
+}
+
+impl ContractFramework {
+    const SUPPORTED: &'static str = "pytest, jest";
+
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "pytest" | "python" => Ok(ContractFramework::Pytest),
+            "jest" | "javascript" | "js" => Ok(ContractFramework::Jest),
+            _ => Err(format!(
+                "contract tests are not supported for '{}'; supported frameworks: {}",
+                name,
+                Self::SUPPORTED
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ContractFramework::Pytest => "pytest",
+            ContractFramework::Jest => "jest",
+        }
+    }
+}
+
+/// A representation of the designed API that `design_api` can return.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    OpenApi,
+    /// A Postman v2.1 collection with one request per operation.
+    Postman,
+    /// A GraphQL schema with reads as queries and writes as mutations.
+    GraphQl,
+}
+
+impl OutputFormat {
+    const SUPPORTED: &'static str = "openapi, postman, graphql";
+
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "openapi" => Ok(OutputFormat::OpenApi),
+            "postman" => Ok(OutputFormat::Postman),
+            "graphql" => Ok(OutputFormat::GraphQl),
+            _ => Err(format!(
+                "unknown output format '{}'; supported formats: {}",
+                name,
+                Self::SUPPORTED
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            OutputFormat::OpenApi => "openapi",
+            OutputFormat::Postman => "postman",
+            OutputFormat::GraphQl => "graphql",
+        }
+    }
+
+    /// Render `spec`, as built by `design_spec`, in this format.
+    fn render(self, spec: &serde_json::Value, auth_type: &str) -> String {
+        match self {
+            OutputFormat::OpenApi => serde_json::to_string_pretty(spec).unwrap(),
+            OutputFormat::Postman => {
+                serde_json::to_string_pretty(&postman_collection(spec, auth_type)).unwrap()
+            }
+            OutputFormat::GraphQl => graphql_schema(spec),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ScaffoldLanguage {
+    Rust,
+    Python,
+    JavaScript,
+}
+
+impl ScaffoldLanguage {
+    const SUPPORTED: &'static str = "rust, python, javascript";
+
+    fn parse(name: &str) -> Result<Self, String> {
+        match name.trim().to_lowercase().as_str() {
+            "rust" => Ok(ScaffoldLanguage::Rust),
+            "python" | "py" => Ok(ScaffoldLanguage::Python),
+            "javascript" | "js" | "node" => Ok(ScaffoldLanguage::JavaScript),
+            _ => Err(format!(
+                "scaffolding is not supported for '{}'; supported languages: {}",
+                name,
+                Self::SUPPORTED
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ScaffoldLanguage::Rust => "rust",
+            ScaffoldLanguage::Python => "python",
+            ScaffoldLanguage::JavaScript => "javascript",
+        }
+    }
+
+    fn framework(self) -> &'static str {
+        match self {
+            ScaffoldLanguage::Rust => "actix-web",
+            ScaffoldLanguage::Python => "fastapi",
+            ScaffoldLanguage::JavaScript => "express",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ValidateRequest {
+    /// OpenAPI 3.0 or 3.1 document, as JSON or YAML.
+    spec: String,
+}
+
+/// A rule of the OpenAPI specification the document breaks.
+#[derive(Debug, PartialEq, Serialize)]
+struct SpecError {
+    /// JSON Pointer to the offending value; empty for the document itself.
+    path: String,
+    message: String,
+}
+
+/// A structurally valid but questionable part of the document; see `lint_openapi`.
+#[derive(Debug, PartialEq, Serialize)]
+struct LintFinding {
+    path: String,
+    rule: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ValidateResponse {
+    /// Whether `errors` is empty; lint findings don't make a document invalid.
+    valid: bool,
+    /// `json` or `yaml`, as detected.
+    format: &'static str,
+    /// The document's `openapi` field, when it has one.
+    openapi_version: Option<String>,
+    errors: Vec<SpecError>,
+    lint: Vec<LintFinding>,
+}
+
+const SUPPORTED_CHANGES: &str = "add <METHOD> <path>[: description], remove <METHOD> <path>, \
+remove <path>, describe <METHOD> <path>: description, set title <title>, set version <version>";
+
 struct AppState {
     designs_count: Mutex<u64>,
 }
 
+/// Error response for a request body that couldn't be read as JSON. `code` is
+/// `invalid_json` for a body that isn't valid JSON and `invalid_request` for JSON that
+/// doesn't fit the endpoint; `line` and `column` (1-based) are where parsing stopped.
+#[derive(Debug, Deserialize, Serialize)]
+struct ErrorBody {
+    error: String,
+    code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    column: Option<usize>,
+}
+
+impl ErrorBody {
+    fn from_json_error(err: &actix_web::error::JsonPayloadError) -> Self {
+        use actix_web::error::JsonPayloadError;
+
+        match err {
+            JsonPayloadError::Deserialize(e) => ErrorBody {
+                error: format!("request body is not valid: {}", e),
+                code: if e.is_data() {
+                    "invalid_request"
+                } else {
+                    "invalid_json"
+                }
+                .to_string(),
+                line: Some(e.line()),
+                column: Some(e.column()),
+            },
+            _ => ErrorBody {
+                error: err.to_string(),
+                code: "invalid_json".to_string(),
+                line: None,
+                column: None,
+            },
+        }
+    }
+}
+
+/// Extractor config for every JSON endpoint: malformed bodies get an `ErrorBody`, which
+/// keeps the `error` field of the other 400s.
+fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err, _| {
+        let response = HttpResponse::BadRequest().json(ErrorBody::from_json_error(&err));
+        actix_web::error::InternalError::from_response(err, response).into()
+    })
+}
+
 async fn health() -> impl Responder {
     HttpResponse::Ok().json(serde_json::json!({
         "status": "healthy",
@@ -46,32 +397,31 @@ async fn design_api(
     req: web::Json<APIDesignRequest>,
     data: web::Data<AppState>,
 ) -> impl Responder {
+    let formats: Result<Vec<OutputFormat>, String> = match req.output_formats.as_slice() {
+        [] => Ok(vec![OutputFormat::OpenApi]),
+        names => names.iter().map(|name| OutputFormat::parse(name)).collect(),
+    };
+    let (spec, formats) = match design_spec(&req).and_then(|spec| Ok((spec, formats?))) {
+        Ok(design) => design,
+        Err(error) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": error }))
+        }
+    };
+    let artifacts = formats
+        .into_iter()
+        .map(|format| {
+            (
+                format.name().to_string(),
+                format.render(&spec, &req.auth_type),
+            )
+        })
+        .collect();
     let mut count = data.designs_count.lock().unwrap();
     *count += 1;
 
-    let openapi_spec = format!(
-        r#"{{
-  "openapi": "3.0.0",
-  "info": {{
-    "title": "{}",
-    "version": "1.0.0"
-  }},
-  "paths": {{
-    "{}": {{
-      "{}": {{
-        "description": "{}"
-      }}
-    }}
-  }}
-}}"#,
-        req.service_name,
-        req.endpoints[0].path,
-        req.endpoints[0].method.to_lowercase(),
-        req.endpoints[0].description
-    );
-
     let response = APIDesignResponse {
-        openapi_spec,
+        openapi_spec: serde_json::to_string_pretty(&spec).unwrap(),
+        artifacts,
         best_practices: vec![
             "Use RESTful conventions".to_string(),
             "Implement proper error handling".to_string(),
@@ -88,6 +438,1646 @@ async fn design_api(
     HttpResponse::Ok().json(response)
 }
 
+/// OpenAPI document with one operation per endpoint. Endpoints repeating a method and path
+/// are rejected rather than silently merged.
+fn design_spec(req: &APIDesignRequest) -> Result<serde_json::Value, String> {
+    if req.endpoints.is_empty() {
+        return Err("endpoints must list at least one endpoint".to_string());
+    }
+    let mut paths = serde_json::Map::new();
+    for endpoint in &req.endpoints {
+        if !endpoint.path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", endpoint.path));
+        }
+        let item = paths
+            .entry(endpoint.path.clone())
+            .or_insert_with(|| serde_json::json!({}));
+        if item.get(endpoint.method.key()).is_some() {
+            return Err(format!(
+                "duplicate endpoint {} {}",
+                endpoint.method, endpoint.path
+            ));
+        }
+        item[endpoint.method.key()] = serde_json::json!({ "description": endpoint.description });
+    }
+    Ok(serde_json::json!({
+        "openapi": "3.0.0",
+        "info": { "title": req.service_name, "version": "1.0.0" },
+        "paths": paths,
+    }))
+}
+
+/// Split `"POST /users: Create a user"` into the method, path and optional description.
+fn parse_operation(rest: &str) -> Result<(HttpMethod, String, Option<String>), String> {
+    let (target, description) = match rest.split_once(':') {
+        Some((target, description)) => (target, Some(description.trim().to_string())),
+        None => (rest, None),
+    };
+    let mut parts = target.split_whitespace();
+    let (Some(method), Some(path), None) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(format!("expected '<METHOD> <path>', got '{}'", rest.trim()));
+    };
+    Ok((method.parse()?, path.to_string(), description))
+}
+
+/// Apply one change line to `spec` in place.
+fn apply_change(spec: &mut serde_json::Value, change: &str) -> Result<(), String> {
+    let (verb, rest) = change
+        .split_once(char::is_whitespace)
+        .unwrap_or((change, ""));
+    let rest = rest.trim();
+    let paths = spec["paths"]
+        .as_object_mut()
+        .ok_or("spec has no 'paths' object")?;
+
+    match verb.to_lowercase().as_str() {
+        "add" => {
+            let (method, path, description) = parse_operation(rest)?;
+            let item = paths
+                .entry(path.clone())
+                .or_insert_with(|| serde_json::json!({}));
+            let item = item
+                .as_object_mut()
+                .ok_or(format!("path '{}' is not an object", path))?;
+            if item.contains_key(method.key()) {
+                return Err(format!("{} {} already exists", method, path));
+            }
+            item.insert(
+                method.key().to_string(),
+                serde_json::json!({ "description": description.unwrap_or_default() }),
+            );
+        }
+        "remove" if rest.starts_with('/') => {
+            paths
+                .remove(rest)
+                .ok_or(format!("path '{}' does not exist", rest))?;
+        }
+        "remove" => {
+            let (method, path, _) = parse_operation(rest)?;
+            let item = paths
+                .get_mut(&path)
+                .and_then(|item| item.as_object_mut())
+                .ok_or(format!("path '{}' does not exist", path))?;
+            item.remove(method.key())
+                .ok_or(format!("{} {} does not exist", method, path))?;
+            if item.is_empty() {
+                paths.remove(&path);
+            }
+        }
+        "describe" => {
+            let (method, path, description) = parse_operation(rest)?;
+            let description = description.ok_or("describe needs ': <description>'")?;
+            let operation = paths
+                .get_mut(&path)
+                .and_then(|item| item.get_mut(method.key()))
+                .and_then(|operation| operation.as_object_mut())
+                .ok_or(format!("{} {} does not exist", method, path))?;
+            operation.insert("description".to_string(), description.into());
+        }
+        "set" => {
+            let (field, value) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let field = field.to_lowercase();
+            if !matches!(field.as_str(), "title" | "version") || value.trim().is_empty() {
+                return Err(format!(
+                    "expected 'set title <title>' or 'set version <version>', got '{}'",
+                    change
+                ));
+            }
+            spec["info"][field] = value.trim().into();
+        }
+        _ => return Err(format!("unrecognized change '{}'", change)),
+    }
+    Ok(())
+}
+
+/// Structural checks for an OpenAPI 3 document: version, info and well-formed paths.
+fn validate_openapi(spec: &serde_json::Value) -> Result<(), String> {
+    let version = spec["openapi"]
+        .as_str()
+        .ok_or("missing 'openapi' version")?;
+    if !version.starts_with("3.") {
+        return Err(format!("unsupported OpenAPI version '{}'", version));
+    }
+    for field in ["title", "version"] {
+        if !spec["info"][field].is_string() {
+            return Err(format!("missing 'info.{}'", field));
+        }
+    }
+    let paths = spec["paths"].as_object().ok_or("missing 'paths' object")?;
+    for (path, item) in paths {
+        if !path.starts_with('/') {
+            return Err(format!("path '{}' must start with '/'", path));
+        }
+        let item = item
+            .as_object()
+            .ok_or(format!("path '{}' is not an object", path))?;
+        for (key, operation) in item {
+            if key.parse::<HttpMethod>().is_ok() && !operation.is_object() {
+                return Err(format!("{} {} is not an object", key.to_uppercase(), path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse `spec` and check it is valid OpenAPI before working on it.
+fn parse_spec(spec: &str) -> Result<serde_json::Value, String> {
+    let parsed: serde_json::Value =
+        serde_json::from_str(spec).map_err(|e| format!("spec is not valid JSON: {}", e))?;
+    validate_openapi(&parsed).map_err(|e| format!("invalid spec: {}", e))?;
+    Ok(parsed)
+}
+
+/// Apply `change_description` to `spec` and return the updated spec with the JSON Patch
+/// between them.
+fn update_spec(spec: &str, change_description: &str) -> Result<SpecUpdateResponse, String> {
+    let original = parse_spec(spec)?;
+
+    let mut updated = original.clone();
+    let changes: Vec<&str> = change_description
+        .split(['\n', ';'])
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    if changes.is_empty() {
+        return Err(format!(
+            "change_description is empty; supported changes: {}",
+            SUPPORTED_CHANGES
+        ));
+    }
+    for change in changes {
+        apply_change(&mut updated, change)
+            .map_err(|e| format!("{}; supported changes: {}", e, SUPPORTED_CHANGES))?;
+    }
+
+    // Check the patch itself rather than `updated`, so what clients apply is what was validated
+    let patch = json_patch::diff(&original, &updated);
+    let mut patched = original;
+    json_patch::patch(&mut patched, &patch)
+        .map_err(|e| format!("generated patch does not apply: {}", e))?;
+    validate_openapi(&patched).map_err(|e| format!("update produces an invalid spec: {}", e))?;
+
+    Ok(SpecUpdateResponse {
+        patch,
+        updated_spec: serde_json::to_string_pretty(&patched).unwrap(),
+    })
+}
+
+/// Handler name for an operation: its `operationId` if it has one, otherwise the method and
+/// path, e.g. `get_users_id` for `GET /users/{id}`.
+fn handler_name(method: HttpMethod, path: &str, operation: &serde_json::Value) -> String {
+    let raw = match operation["operationId"].as_str() {
+        Some(id) => id.to_string(),
+        None => format!("{}_{}", method.key(), path),
+    };
+    let mut name = String::new();
+    for c in raw.chars() {
+        if c.is_ascii_alphanumeric() {
+            // camelCase operationIds become snake_case
+            if c.is_ascii_uppercase() && name.chars().last().is_some_and(|p| p.is_ascii_alphanumeric()) {
+                name.push('_');
+            }
+            name.push(c.to_ascii_lowercase());
+        } else if !name.ends_with('_') {
+            name.push('_');
+        }
+    }
+    let name = name.trim_matches('_').to_string();
+    if path == "/" && operation["operationId"].is_null() {
+        format!("{}_root", method.key())
+    } else if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("op_{}", name)
+    } else {
+        name
+    }
+}
+
+/// Path parameter names in an OpenAPI path, e.g. `["id"]` for `/users/{id}`.
+fn path_params(path: &str) -> Vec<&str> {
+    path.split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}').map(|(name, _)| name))
+        .collect()
+}
+
+/// `segment` escaped for use in a JSON Pointer.
+fn pointer_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+/// Parse `spec` as JSON when it looks like JSON, otherwise as YAML, and check it against
+/// the OpenAPI 3.0 or 3.1 rules. A document that doesn't parse is reported as invalid
+/// rather than rejected.
+fn validate_document(spec: &str) -> ValidateResponse {
+    let (format, parsed) = if spec.trim_start().starts_with('{') {
+        let parsed = serde_json::from_str(spec).map_err(|e| format!("not valid JSON: {}", e));
+        ("json", parsed)
+    } else {
+        (
+            "yaml",
+            parse_yaml(spec).map_err(|e| format!("not valid YAML: {}", e)),
+        )
+    };
+    let document = match parsed {
+        Ok(document) => document,
+        Err(message) => {
+            return ValidateResponse {
+                valid: false,
+                format,
+                openapi_version: None,
+                errors: vec![SpecError {
+                    path: String::new(),
+                    message,
+                }],
+                lint: Vec::new(),
+            }
+        }
+    };
+    let errors = openapi_errors(&document);
+    ValidateResponse {
+        valid: errors.is_empty(),
+        format,
+        openapi_version: document["openapi"].as_str().map(str::to_string),
+        errors,
+        lint: lint_openapi(&document),
+    }
+}
+
+/// Every structural error in an OpenAPI document, checked against 3.1 when its `openapi`
+/// field says so and against 3.0 otherwise. Unlike `validate_openapi`, which guards the
+/// specs this service edits, this follows the specification strictly.
+fn openapi_errors(spec: &serde_json::Value) -> Vec<SpecError> {
+    let mut errors = Vec::new();
+    let mut error = |path: String, message: String| errors.push(SpecError { path, message });
+    if !spec.is_object() {
+        error(String::new(), "document must be an object".to_string());
+        return errors;
+    }
+
+    let v31 = match &spec["openapi"] {
+        serde_json::Value::String(version) if version.starts_with("3.1.") => true,
+        serde_json::Value::String(version) if version.starts_with("3.0.") => false,
+        serde_json::Value::String(version) => {
+            let message = format!("unsupported version '{}'; expected 3.0.x or 3.1.x", version);
+            error("/openapi".to_string(), message);
+            false
+        }
+        serde_json::Value::Null => {
+            error("/openapi".to_string(), "missing required field".to_string());
+            false
+        }
+        _ => {
+            let message = "must be a version string such as \"3.1.0\"".to_string();
+            error("/openapi".to_string(), message);
+            false
+        }
+    };
+
+    match spec["info"].as_object() {
+        Some(info) => {
+            for field in ["title", "version"] {
+                if !info.get(field).is_some_and(|value| value.is_string()) {
+                    error(
+                        format!("/info/{}", field),
+                        "missing required string".to_string(),
+                    );
+                }
+            }
+        }
+        None => error("/info".to_string(), "missing required object".to_string()),
+    }
+
+    match &spec["paths"] {
+        serde_json::Value::Null if v31 => {
+            if spec["components"].is_null() && spec["webhooks"].is_null() {
+                let message = "3.1 documents need at least one of paths, components or webhooks";
+                error(String::new(), message.to_string());
+            }
+        }
+        serde_json::Value::Null => {
+            error("/paths".to_string(), "missing required object".to_string())
+        }
+        serde_json::Value::Object(paths) => {
+            let mut operation_ids: BTreeMap<&str, String> = BTreeMap::new();
+            for (path, item) in paths {
+                let item_path = format!("/paths/{}", pointer_segment(path));
+                if !path.starts_with('/') {
+                    error(
+                        item_path.clone(),
+                        format!("path '{}' must start with '/'", path),
+                    );
+                }
+                if !item.is_object() {
+                    error(item_path, "path item must be an object".to_string());
+                    continue;
+                }
+                let shared = parameter_errors(&item["parameters"], &item_path, &mut error);
+                for key in HttpMethod::ALL.map(HttpMethod::key) {
+                    let Some(operation) = item.get(key) else {
+                        continue;
+                    };
+                    let operation_path = format!("{}/{}", item_path, key);
+                    if !operation.is_object() {
+                        error(operation_path, "operation must be an object".to_string());
+                        continue;
+                    }
+                    let own =
+                        parameter_errors(&operation["parameters"], &operation_path, &mut error);
+                    for name in path_params(path) {
+                        if !shared.iter().chain(&own).any(|declared| *declared == name) {
+                            let message = format!("path parameter '{}' is not declared", name);
+                            error(operation_path.clone(), message);
+                        }
+                    }
+                    if let Some(id) = operation["operationId"].as_str() {
+                        if let Some(first) = operation_ids.insert(id, operation_path.clone()) {
+                            let message = format!("operationId '{}' is also used by {}", id, first);
+                            error(format!("{}/operationId", operation_path), message);
+                        }
+                    }
+                    response_errors(&operation["responses"], &operation_path, v31, &mut error);
+                }
+            }
+        }
+        _ => error("/paths".to_string(), "must be an object".to_string()),
+    }
+
+    let mut schemas = Vec::new();
+    collect_schemas(spec, String::new(), &mut schemas);
+    for (path, schema) in schemas {
+        schema_errors(schema, &path, v31, &mut error);
+    }
+    ref_errors(spec, spec, String::new(), &mut error);
+    errors
+}
+
+/// Checks a `parameters` list and returns the names of the path parameters it declares.
+fn parameter_errors<'a>(
+    parameters: &'a serde_json::Value,
+    path: &str,
+    error: &mut impl FnMut(String, String),
+) -> Vec<&'a str> {
+    let mut declared = Vec::new();
+    let parameters = match parameters {
+        serde_json::Value::Null => return declared,
+        serde_json::Value::Array(parameters) => parameters,
+        _ => {
+            error(
+                format!("{}/parameters", path),
+                "must be an array".to_string(),
+            );
+            return declared;
+        }
+    };
+    for (i, parameter) in parameters.iter().enumerate() {
+        let parameter_path = format!("{}/parameters/{}", path, i);
+        if parameter.get("$ref").is_some() {
+            continue;
+        }
+        let Some(name) = parameter["name"].as_str() else {
+            error(
+                format!("{}/name", parameter_path),
+                "missing required string".to_string(),
+            );
+            continue;
+        };
+        match parameter["in"].as_str() {
+            Some("path") => {
+                if parameter["required"] != serde_json::Value::Bool(true) {
+                    let message = format!("path parameter '{}' must be required", name);
+                    error(format!("{}/required", parameter_path), message);
+                }
+                declared.push(name);
+            }
+            Some("query" | "header" | "cookie") => {}
+            _ => {
+                let message = "must be one of query, header, path or cookie".to_string();
+                error(format!("{}/in", parameter_path), message);
+            }
+        }
+    }
+    declared
+}
+
+/// Checks an operation's `responses`, which 3.0 requires and 3.1 makes optional.
+fn response_errors(
+    responses: &serde_json::Value,
+    path: &str,
+    v31: bool,
+    error: &mut impl FnMut(String, String),
+) {
+    let responses_path = format!("{}/responses", path);
+    let responses = match responses {
+        serde_json::Value::Null if v31 => return,
+        serde_json::Value::Null => {
+            error(responses_path, "missing required object".to_string());
+            return;
+        }
+        serde_json::Value::Object(responses) => responses,
+        _ => {
+            error(responses_path, "must be an object".to_string());
+            return;
+        }
+    };
+    if responses.is_empty() {
+        error(
+            responses_path.clone(),
+            "must document at least one response".to_string(),
+        );
+    }
+    for (status, response) in responses {
+        let response_path = format!("{}/{}", responses_path, pointer_segment(status));
+        let valid_status = status == "default"
+            || (status.len() == 3
+                && matches!(status.as_bytes()[0], b'1'..=b'5')
+                && (status[1..].bytes().all(|b| b.is_ascii_digit()) || &status[1..] == "XX"));
+        if !valid_status {
+            let message = format!(
+                "'{}' is not a status code, a range like 4XX or default",
+                status
+            );
+            error(response_path.clone(), message);
+        }
+        if response.get("$ref").is_none() && !response["description"].is_string() {
+            error(
+                format!("{}/description", response_path),
+                "missing required string".to_string(),
+            );
+        }
+    }
+}
+
+/// Every schema object in the document with its pointer: component schemas, `schema`
+/// fields anywhere, and the schemas nested in those.
+fn collect_schemas<'a>(
+    value: &'a serde_json::Value,
+    path: String,
+    schemas: &mut Vec<(String, &'a serde_json::Value)>,
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map {
+                let child_path = format!("{}/{}", path, pointer_segment(key));
+                if key == "schema" && child.is_object() {
+                    schemas.push((child_path.clone(), child));
+                } else if key == "schemas" && path == "/components" {
+                    for (name, schema) in child.as_object().into_iter().flatten() {
+                        schemas.push((format!("{}/{}", child_path, pointer_segment(name)), schema));
+                    }
+                    continue;
+                }
+                collect_schemas(child, child_path, schemas);
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                collect_schemas(item, format!("{}/{}", path, i), schemas);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Checks the parts of a schema whose meaning differs between 3.0 and 3.1, recursing into
+/// its subschemas: 3.0 allows only a single `type`, and 3.1 drops `nullable` in favour of
+/// a `"null"` type.
+fn schema_errors(
+    schema: &serde_json::Value,
+    path: &str,
+    v31: bool,
+    error: &mut impl FnMut(String, String),
+) {
+    let Some(map) = schema.as_object() else {
+        return;
+    };
+    if !v31 && map.get("type").is_some_and(|t| !t.is_string()) {
+        error(format!("{}/type", path), "must be a single type name in 3.0".to_string());
+    }
+    if v31 && map.contains_key("nullable") {
+        let message = "nullable was removed in 3.1; add \"null\" to type instead".to_string();
+        error(format!("{}/nullable", path), message);
+    }
+    for key in ["items", "not", "additionalProperties"] {
+        if let Some(child) = map.get(key) {
+            schema_errors(child, &format!("{}/{}", path, key), v31, error);
+        }
+    }
+    for key in ["allOf", "anyOf", "oneOf"] {
+        for (i, child) in map
+            .get(key)
+            .and_then(|c| c.as_array())
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            schema_errors(child, &format!("{}/{}/{}", path, key, i), v31, error);
+        }
+    }
+    for (name, child) in map
+        .get("properties")
+        .and_then(|p| p.as_object())
+        .into_iter()
+        .flatten()
+    {
+        let child_path = format!("{}/properties/{}", path, pointer_segment(name));
+        schema_errors(child, &child_path, v31, error);
+    }
+}
+
+/// Reports local `$ref`s (`#/...`) that point at nothing. External references aren't followed.
+fn ref_errors(
+    spec: &serde_json::Value,
+    value: &serde_json::Value,
+    path: String,
+    error: &mut impl FnMut(String, String),
+) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map {
+                let child_path = format!("{}/{}", path, pointer_segment(key));
+                match (key.as_str(), child.as_str()) {
+                    ("$ref", Some(target)) if target.starts_with('#') => {
+                        if spec.pointer(&target[1..]).is_none() {
+                            error(child_path, format!("'{}' does not resolve", target));
+                        }
+                    }
+                    _ => ref_errors(spec, child, child_path, error),
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                ref_errors(spec, item, format!("{}/{}", path, i), error);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Style findings for the operations of a document: missing `operationId`s and
+/// descriptions, no documented error responses, and paths with a trailing slash.
+fn lint_openapi(spec: &serde_json::Value) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    for (path, item) in spec["paths"].as_object().into_iter().flatten() {
+        let item_path = format!("/paths/{}", pointer_segment(path));
+        if path.len() > 1 && path.ends_with('/') {
+            findings.push(LintFinding {
+                path: item_path.clone(),
+                rule: "path-trailing-slash",
+                message: format!("'{}' ends with '/'", path),
+            });
+        }
+        for key in HttpMethod::ALL.map(HttpMethod::key) {
+            let Some(operation) = item.get(key).and_then(|operation| operation.as_object()) else {
+                continue;
+            };
+            let operation_path = format!("{}/{}", item_path, key);
+            let name = format!("{} {}", key.to_uppercase(), path);
+            if !operation.contains_key("operationId") {
+                findings.push(LintFinding {
+                    path: operation_path.clone(),
+                    rule: "operation-id",
+                    message: format!("{} has no operationId for clients to name it by", name),
+                });
+            }
+            if !operation.contains_key("summary") && !operation.contains_key("description") {
+                findings.push(LintFinding {
+                    path: operation_path.clone(),
+                    rule: "operation-description",
+                    message: format!("{} has no summary or description", name),
+                });
+            }
+            if let Some(responses) = operation.get("responses").and_then(|r| r.as_object()) {
+                let documents_errors = responses
+                    .keys()
+                    .any(|status| status == "default" || status.starts_with(['4', '5']));
+                if !documents_errors {
+                    findings.push(LintFinding {
+                        path: format!("{}/responses", operation_path),
+                        rule: "error-responses",
+                        message: format!("{} documents no 4xx, 5xx or default response", name),
+                    });
+                }
+            }
+        }
+    }
+    findings
+}
+
+/// One meaningful line of a YAML document: its indentation and its text without the
+/// indentation or a trailing comment. Lines of a block scalar are kept verbatim, and its
+/// blank lines have an empty `text` and `usize::MAX` as `indent`.
+struct YamlLine<'a> {
+    number: usize,
+    indent: usize,
+    text: &'a str,
+}
+
+/// Parses the block-style subset of YAML that OpenAPI documents are written in: nested
+/// mappings and `- ` sequences, plain, quoted and `|`/`>` block scalars, and flow `[...]`
+/// and `{...}` collections. Anchors, aliases, tags and multiple documents are rejected.
+fn parse_yaml(source: &str) -> Result<serde_json::Value, String> {
+    let mut lines = Vec::new();
+    // Indentation of the entry whose `|` or `>` block scalar is being read.
+    let mut block_parent = None;
+    for (i, raw) in source.lines().enumerate() {
+        if let Some(parent) = block_parent {
+            let content = raw.trim_start_matches(' ');
+            let indent = raw.len() - content.len();
+            if content.trim().is_empty() {
+                lines.push(YamlLine {
+                    number: i + 1,
+                    indent: usize::MAX,
+                    text: "",
+                });
+                continue;
+            }
+            if indent > parent {
+                lines.push(YamlLine {
+                    number: i + 1,
+                    indent,
+                    text: content,
+                });
+                continue;
+            }
+            block_parent = None;
+        }
+        let text = strip_yaml_comment(raw).trim_end();
+        let content = text.trim_start_matches(' ');
+        if content.starts_with('\t') {
+            return Err(format!(
+                "line {}: tabs can't be used for indentation",
+                i + 1
+            ));
+        }
+        if content.is_empty() || (lines.is_empty() && content == "---") {
+            continue;
+        }
+        if content == "---" || content == "..." {
+            return Err(format!("line {}: only one document is supported", i + 1));
+        }
+        let indent = text.len() - content.len();
+        let mut entry = content;
+        while YamlParser::is_item(entry) {
+            entry = entry[1..].trim_start_matches(' ');
+        }
+        if split_yaml_entry(entry).is_some_and(|(_, rest)| rest.starts_with(['|', '>'])) {
+            block_parent = Some(indent);
+        }
+        lines.push(YamlLine {
+            number: i + 1,
+            indent,
+            text: content,
+        });
+    }
+    let mut parser = YamlParser { lines, pos: 0 };
+    if parser.lines.is_empty() {
+        return Ok(serde_json::Value::Null);
+    }
+    let value = parser.block()?;
+    match parser.lines.get(parser.pos) {
+        Some(line) => Err(format!("line {}: unexpected indentation", line.number)),
+        None => Ok(value),
+    }
+}
+
+/// `line` up to a `#` comment that is outside quotes and starts the line or follows a space.
+fn strip_yaml_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '#') if previous == ' ' || previous == '\t' => return &line[..i],
+            (None, '"' | '\'') if previous == ' ' || previous == ':' || previous == '-' || i == 0 => {
+                quote = Some(c)
+            }
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+        previous = c;
+    }
+    line
+}
+
+struct YamlParser<'a> {
+    lines: Vec<YamlLine<'a>>,
+    pos: usize,
+}
+
+impl<'a> YamlParser<'a> {
+    fn is_item(text: &str) -> bool {
+        text == "-" || text.starts_with("- ")
+    }
+
+    /// The mapping or sequence starting at the current line.
+    fn block(&mut self) -> Result<serde_json::Value, String> {
+        let line = &self.lines[self.pos];
+        if Self::is_item(line.text) {
+            self.sequence(line.indent)
+        } else {
+            self.mapping(line.indent)
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<serde_json::Value, String> {
+        let mut items = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent != indent || !Self::is_item(line.text) {
+                break;
+            }
+            let rest = line.text[1..].trim_start_matches(' ');
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.nested(indent)?);
+            } else if !rest.starts_with(['[', '{']) && split_yaml_entry(rest).is_some() {
+                // `- key: value` opens a mapping indented to where `key` starts
+                let offset = line.indent + (line.text.len() - rest.len());
+                self.lines[self.pos].indent = offset;
+                self.lines[self.pos].text = rest;
+                items.push(self.mapping(offset)?);
+            } else {
+                let number = line.number;
+                self.pos += 1;
+                items.push(yaml_inline(rest, number)?);
+            }
+        }
+        Ok(serde_json::Value::Array(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<serde_json::Value, String> {
+        let mut map = serde_json::Map::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent < indent {
+                break;
+            }
+            let number = line.number;
+            if line.indent > indent || Self::is_item(line.text) {
+                return Err(format!("line {}: unexpected indentation", number));
+            }
+            let (key, rest) = split_yaml_entry(line.text)
+                .ok_or_else(|| format!("line {}: expected 'key: value'", number))?;
+            self.pos += 1;
+            let value = if rest.is_empty() {
+                match self.lines.get(self.pos) {
+                    Some(next) if next.indent == indent && Self::is_item(next.text) => {
+                        self.sequence(indent)?
+                    }
+                    _ => self.nested(indent)?,
+                }
+            } else if rest.starts_with(['|', '>']) {
+                self.block_scalar(indent, rest, number)?
+            } else {
+                yaml_inline(rest, number)?
+            };
+            if map.insert(key.clone(), value).is_some() {
+                return Err(format!("line {}: duplicate key '{}'", number, key));
+            }
+        }
+        Ok(serde_json::Value::Object(map))
+    }
+
+    /// The block indented under the previous line, or null if there is none.
+    fn nested(&mut self, parent_indent: usize) -> Result<serde_json::Value, String> {
+        match self.lines.get(self.pos) {
+            Some(next) if next.indent > parent_indent => self.block(),
+            _ => Ok(serde_json::Value::Null),
+        }
+    }
+
+    /// A `|` (literal) or `>` (folded) scalar: the lines indented under its key.
+    fn block_scalar(
+        &mut self,
+        parent_indent: usize,
+        header: &str,
+        number: usize,
+    ) -> Result<serde_json::Value, String> {
+        if !matches!(header, "|" | ">" | "|-" | ">-" | "|+" | ">+") {
+            return Err(format!("line {}: unsupported block scalar '{}'", number, header));
+        }
+        let start = self.pos;
+        while self
+            .lines
+            .get(self.pos)
+            .is_some_and(|line| line.indent > parent_indent)
+        {
+            self.pos += 1;
+        }
+        let lines = &self.lines[start..self.pos];
+        let content = lines.iter().rposition(|line| !line.text.is_empty()).map_or(0, |i| i + 1);
+        let (lines, trailing_blanks) = (&lines[..content], lines.len() - content);
+        let base = lines
+            .iter()
+            .filter(|line| !line.text.is_empty())
+            .map(|line| line.indent)
+            .min()
+            .unwrap_or(0);
+        let texts: Vec<String> = lines
+            .iter()
+            .map(|line| match line.text {
+                "" => String::new(),
+                text => format!("{}{}", " ".repeat(line.indent - base), text),
+            })
+            .collect();
+        let mut text = if header.starts_with('|') {
+            texts.join("\n")
+        } else {
+            // Folded: lines join with a space, and each blank line becomes a line break.
+            let mut folded = String::new();
+            for (i, line) in texts.iter().enumerate() {
+                if line.is_empty() {
+                    folded.push('\n');
+                } else {
+                    if i > 0 && !texts[i - 1].is_empty() {
+                        folded.push(' ');
+                    }
+                    folded.push_str(line);
+                }
+            }
+            folded
+        };
+        if !header.ends_with('-') && !text.is_empty() {
+            text.push('\n');
+        }
+        if header.ends_with('+') {
+            text.push_str(&"\n".repeat(trailing_blanks));
+        }
+        Ok(text.into())
+    }
+}
+
+/// Splits `key: value` (or `key:`) at the first colon outside quotes that ends the line or
+/// is followed by a space; the key is unquoted.
+fn split_yaml_entry(text: &str) -> Option<(String, &str)> {
+    let (key, rest) = if text.starts_with(['"', '\'']) {
+        let (key, len) = yaml_quoted(text).ok()?;
+        (key, text[len..].trim_start_matches(' ').strip_prefix(':')?)
+    } else {
+        let colon = text
+            .match_indices(':')
+            .map(|(i, _)| i)
+            .find(|&i| text[i + 1..].is_empty() || text[i + 1..].starts_with(' '))?;
+        (text[..colon].trim_end().to_string(), &text[colon + 1..])
+    };
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    Some((key, rest.trim_start_matches(' ')))
+}
+
+/// A value written on the same line as its key or dash: a flow collection or a scalar.
+fn yaml_inline(text: &str, number: usize) -> Result<serde_json::Value, String> {
+    if text.starts_with(['&', '*', '!']) {
+        return Err(format!(
+            "line {}: anchors, aliases and tags are not supported",
+            number
+        ));
+    }
+    let mut flow = YamlFlow { text, pos: 0 };
+    let value = flow
+        .value()
+        .map_err(|e| format!("line {}: {}", number, e))?;
+    if !flow.text[flow.pos..].trim().is_empty() {
+        return Err(format!(
+            "line {}: unexpected '{}'",
+            number,
+            &flow.text[flow.pos..]
+        ));
+    }
+    Ok(value)
+}
+
+/// Reads a double- or single-quoted scalar at the start of `text`, returning it and the
+/// number of bytes it took up.
+fn yaml_quoted(text: &str) -> Result<(String, usize), String> {
+    let quote = text.chars().next().ok_or("expected a quoted string")?;
+    let mut value = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\'' if quote == '\'' && chars.peek().is_some_and(|&(_, next)| next == '\'') => {
+                chars.next();
+                value.push('\'');
+            }
+            c if c == quote => return Ok((value, i + 1)),
+            '\\' if quote == '"' => match chars.next().map(|(_, escaped)| escaped) {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(escaped @ ('"' | '\\' | '/')) => value.push(escaped),
+                other => return Err(format!("unsupported escape '\\{}'", other.unwrap_or(' '))),
+            },
+            c => value.push(c),
+        }
+    }
+    Err("unterminated quoted string".to_string())
+}
+
+/// A plain scalar as JSON: null, a boolean, a number, or otherwise the text itself.
+fn yaml_plain(text: &str) -> serde_json::Value {
+    match text {
+        "" | "~" | "null" | "Null" | "NULL" => return serde_json::Value::Null,
+        "true" | "True" | "TRUE" => return true.into(),
+        "false" | "False" | "FALSE" => return false.into(),
+        _ => {}
+    }
+    let numeric = text
+        .trim_start_matches(['-', '+'])
+        .starts_with(|c: char| c.is_ascii_digit() || c == '.');
+    if numeric {
+        if let Ok(integer) = text.parse::<i64>() {
+            return integer.into();
+        }
+        if let Some(number) = text.parse::<f64>().ok().and_then(serde_json::Number::from_f64) {
+            return number.into();
+        }
+    }
+    text.into()
+}
+
+/// Reads flow collections (`[a, b]`, `{key: value}`) and the scalars inside them.
+struct YamlFlow<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl YamlFlow<'_> {
+    fn skip_spaces(&mut self) {
+        self.pos += self.text[self.pos..].len() - self.text[self.pos..].trim_start().len();
+    }
+
+    fn value(&mut self) -> Result<serde_json::Value, String> {
+        self.skip_spaces();
+        let rest = &self.text[self.pos..];
+        if rest.starts_with(['"', '\'']) {
+            let (value, len) = yaml_quoted(rest)?;
+            self.pos += len;
+            return Ok(value.into());
+        }
+        if rest.starts_with('[') {
+            self.pos += 1;
+            let mut items = Vec::new();
+            while !self.close(']')? {
+                items.push(self.value()?);
+            }
+            return Ok(serde_json::Value::Array(items));
+        }
+        if rest.starts_with('{') {
+            self.pos += 1;
+            let mut map = serde_json::Map::new();
+            while !self.close('}')? {
+                let key = match self.value()? {
+                    serde_json::Value::String(key) => key,
+                    other => other.to_string(),
+                };
+                self.skip_spaces();
+                if !self.text[self.pos..].starts_with(':') {
+                    return Err(format!("expected ':' after '{}'", key));
+                }
+                self.pos += 1;
+                map.insert(key, self.value()?);
+            }
+            return Ok(serde_json::Value::Object(map));
+        }
+        // Inside a collection a plain scalar ends at the next delimiter; outside it, at the end
+        let nested = self.pos > 0;
+        let end = if nested {
+            rest.find([',', ']', '}', ':']).unwrap_or(rest.len())
+        } else {
+            rest.len()
+        };
+        self.pos += end;
+        Ok(yaml_plain(rest[..end].trim_end()))
+    }
+
+    /// Consumes a `,` between items, returning true once `closing` has been consumed.
+    fn close(&mut self, closing: char) -> Result<bool, String> {
+        self.skip_spaces();
+        let rest = &self.text[self.pos..];
+        if rest.starts_with(closing) {
+            self.pos += 1;
+            return Ok(true);
+        }
+        if rest.starts_with(',') {
+            self.pos += 1;
+            self.skip_spaces();
+            if self.text[self.pos..].starts_with(closing) {
+                self.pos += 1;
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+        if self.text[..self.pos].trim_end().ends_with(['[', '{']) {
+            return Ok(false);
+        }
+        if rest.is_empty() {
+            return Err(format!("missing '{}'", closing));
+        }
+        Err(format!("expected ',' or '{}', got '{}'", closing, rest))
+    }
+}
+
+/// Render one stub per operation in `spec` for `language`'s framework. Stubs respond 501.
+fn scaffold(
+    spec: &serde_json::Value,
+    language: ScaffoldLanguage,
+) -> (Vec<ScaffoldHandler>, String) {
+    let mut handlers: Vec<ScaffoldHandler> = Vec::new();
+    let mut stubs = String::new();
+    let paths = spec["paths"].as_object().cloned().unwrap_or_default();
+
+    for (path, item) in &paths {
+        for method in HttpMethod::ALL {
+            let Some(operation) = item.get(method.key()) else {
+                continue;
+            };
+            let base = handler_name(method, path, operation);
+            let mut name = base.clone();
+            let mut suffix = handlers.len() + 1;
+            while handlers.iter().any(|h| h.name == name) {
+                name = format!("{}_{}", base, suffix);
+                suffix += 1;
+            }
+            let description = operation["description"]
+                .as_str()
+                .or(operation["summary"].as_str())
+                .unwrap_or("")
+                .trim();
+            let params = path_params(path);
+
+            match language {
+                ScaffoldLanguage::Rust => {
+                    if !description.is_empty() {
+                        stubs.push_str(&line_comment("///", description));
+                    }
+                    let args = match params.len() {
+                        0 => String::new(),
+                        1 => "path: web::Path<String>".to_string(),
+                        n => format!("path: web::Path<({})>", vec!["String"; n].join(", ")),
+                    };
+                    stubs.push_str(&format!(
+                        "async fn {}({}) -> impl Responder {{\n    {}HttpResponse::NotImplemented().finish()\n}}\n\n",
+                        name,
+                        args,
+                        if params.is_empty() { "" } else { "let _ = path.into_inner();\n    " }
+                    ));
+                }
+                ScaffoldLanguage::Python => {
+                    let args: Vec<String> = params.iter().map(|p| format!("{}: str", p)).collect();
+                    stubs.push_str(&format!("@app.{}(\"{}\")\nasync def {}({}):\n", method.key(), path, name, args.join(", ")));
+                    if !description.is_empty() {
+                        // Escaped so no quote in the text can close the docstring early;
+                        // continuation lines are indented with the body.
+                        let docstring = description
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\n    ");
+                        stubs.push_str(&format!("    \"\"\"{}\"\"\"\n", docstring));
+                    }
+                    stubs.push_str("    raise HTTPException(status_code=501, detail=\"Not implemented\")\n\n\n");
+                }
+                ScaffoldLanguage::JavaScript => {
+                    let express_path = params
+                        .iter()
+                        .fold(path.clone(), |p, param| p.replace(&format!("{{{}}}", param), &format!(":{}", param)));
+                    if !description.is_empty() {
+                        stubs.push_str(&line_comment("//", description));
+                    }
+                    stubs.push_str(&format!(
+                        "app.{}(\"{}\", function {}(req, res) {{\n  res.status(501).json({{ error: \"Not implemented\" }});\n}});\n\n",
+                        method.key(), express_path, to_camel_case(&name)
+                    ));
+                }
+            }
+            handlers.push(ScaffoldHandler {
+                method,
+                path: path.clone(),
+                name,
+            });
+        }
+    }
+
+    let title = spec["info"]["title"].as_str().unwrap_or("api");
+    let code = match language {
+        ScaffoldLanguage::Rust => {
+            let routes: String = handlers
+                .iter()
+                .map(|h| format!("\n            .route(\"{}\", web::{}().to({}))", h.path, h.method.key(), h.name))
+                .collect();
+            format!(
+                "// {} server stubs generated from its OpenAPI spec.\n\
+                 use actix_web::{{web, App, HttpResponse, HttpServer, Responder}};\n\n\
+                 {}#[actix_web::main]\n\
+                 async fn main() -> std::io::Result<()> {{\n    \
+                 HttpServer::new(|| {{\n        App::new(){}\n    }})\n    \
+                 .bind((\"0.0.0.0\", 8080))?\n    .run()\n    .await\n}}\n",
+                title, stubs, routes
+            )
+        }
+        ScaffoldLanguage::Python => format!(
+            "\"\"\"{} server stubs generated from its OpenAPI spec.\"\"\"\n\
+             from fastapi import FastAPI, HTTPException\n\n\
+             app = FastAPI(title=\"{}\")\n\n\n{}",
+            title,
+            title,
+            stubs.trim_end_matches('\n').to_string() + "\n"
+        ),
+        ScaffoldLanguage::JavaScript => format!(
+            "// {} server stubs generated from its OpenAPI spec.\n\
+             const express = require(\"express\");\n\n\
+             const app = express();\napp.use(express.json());\n\n{}\
+             app.listen(8080);\n",
+            title, stubs
+        ),
+    };
+    (handlers, code)
+}
+
+/// `text` as line comments starting with `marker`, one per line.
+fn line_comment(marker: &str, text: &str) -> String {
+    text.lines()
+        .map(|line| format!("{} {}", marker, line).trim_end().to_string() + "\n")
+        .collect()
+}
+
+/// Postman v2.1 collection with a request per operation in `spec`, against a `baseUrl`
+/// variable. Path parameters become `:name` segments with collection-level variables.
+fn postman_collection(spec: &serde_json::Value, auth_type: &str) -> serde_json::Value {
+    let paths = spec["paths"].as_object().cloned().unwrap_or_default();
+    let mut items = Vec::new();
+    for (path, item) in &paths {
+        for method in HttpMethod::ALL {
+            let Some(operation) = item.get(method.key()) else {
+                continue;
+            };
+            let params = path_params(path);
+            let segments: Vec<String> = path
+                .split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(
+                    |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(param) => format!(":{}", param),
+                        None => segment.to_string(),
+                    },
+                )
+                .collect();
+            let name = operation["description"]
+                .as_str()
+                .filter(|d| !d.trim().is_empty())
+                .map_or_else(|| format!("{} {}", method, path), str::to_string);
+            items.push(serde_json::json!({
+                "name": name,
+                "request": {
+                    "method": method.to_string(),
+                    "url": {
+                        "raw": format!("{{{{baseUrl}}}}/{}", segments.join("/")),
+                        "host": ["{{baseUrl}}"],
+                        "path": segments,
+                        "variable": params
+                            .iter()
+                            .map(|p| serde_json::json!({ "key": p, "value": "" }))
+                            .collect::<Vec<_>>(),
+                    },
+                },
+            }));
+        }
+    }
+
+    let mut collection = serde_json::json!({
+        "info": {
+            "name": spec["info"]["title"],
+            "schema": "https://schema.getpostman.com/json/collection/v2.1.0/collection.json",
+        },
+        "item": items,
+        "variable": [{ "key": "baseUrl", "value": "http://localhost:8080" }],
+    });
+    let auth = match auth_type.trim().to_lowercase().as_str() {
+        "bearer" | "jwt" | "oauth2" => Some(serde_json::json!({
+            "type": "bearer",
+            "bearer": [{ "key": "token", "value": "{{token}}", "type": "string" }],
+        })),
+        "api_key" | "apikey" => Some(serde_json::json!({
+            "type": "apikey",
+            "apikey": [{ "key": "value", "value": "{{apiKey}}", "type": "string" }],
+        })),
+        "basic" => Some(serde_json::json!({ "type": "basic", "basic": [] })),
+        _ => None,
+    };
+    if let Some(auth) = auth {
+        collection["auth"] = auth;
+    }
+    collection
+}
+
+/// GraphQL schema for `spec`: `GET` operations become `Query` fields and the rest
+/// `Mutation` fields, named after their handlers. Endpoints carry no schemas, so every
+/// field returns, and non-`GET` fields take, an opaque `JSON` scalar.
+fn graphql_schema(spec: &serde_json::Value) -> String {
+    let paths = spec["paths"].as_object().cloned().unwrap_or_default();
+    let (mut queries, mut mutations) = (String::new(), String::new());
+    let (mut query_names, mut mutation_names) = (Vec::new(), Vec::new());
+    for (path, item) in &paths {
+        for method in HttpMethod::ALL {
+            let Some(operation) = item.get(method.key()) else {
+                continue;
+            };
+            let mut args: Vec<String> = path_params(path)
+                .iter()
+                .map(|p| format!("{}: ID!", to_camel_case(p)))
+                .collect();
+            let (fields, names) = if method == HttpMethod::Get {
+                (&mut queries, &mut query_names)
+            } else {
+                if !matches!(
+                    method,
+                    HttpMethod::Delete | HttpMethod::Head | HttpMethod::Options | HttpMethod::Trace
+                ) {
+                    args.push("input: JSON".to_string());
+                }
+                (&mut mutations, &mut mutation_names)
+            };
+            if let Some(description) = operation["description"]
+                .as_str()
+                .filter(|d| !d.trim().is_empty())
+            {
+                let mut description = description
+                    .trim()
+                    .replace("\"\"\"", "\\\"\"\"")
+                    .replace('\n', "\n  ");
+                // a closing quote run would merge with the block string's terminator
+                if description.ends_with('"') {
+                    description.push_str("\n  ");
+                }
+                fields.push_str(&format!("  \"\"\"{}\"\"\"\n", description));
+            }
+            let args = if args.is_empty() {
+                String::new()
+            } else {
+                format!("({})", args.join(", "))
+            };
+            let base = to_camel_case(&handler_name(method, path, operation));
+            let mut name = base.clone();
+            let mut suffix = names.len() + 1;
+            while names.contains(&name) {
+                name = format!("{}{}", base, suffix);
+                suffix += 1;
+            }
+            names.push(name.clone());
+            fields.push_str(&format!("  {}{}: JSON\n", name, args));
+        }
+    }
+
+    let mut schema = format!(
+        "# GraphQL schema for {}, generated from its OpenAPI spec.\nscalar JSON\n",
+        spec["info"]["title"].as_str().unwrap_or("api")
+    );
+    for (name, fields) in [("Query", queries), ("Mutation", mutations)] {
+        if !fields.is_empty() {
+            schema.push_str(&format!("\ntype {} {{\n{}}}\n", name, fields));
+        }
+    }
+    schema
+}
+
+fn to_camel_case(snake: &str) -> String {
+    let mut camel = String::new();
+    let mut upper = false;
+    for c in snake.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            camel.push(c.to_ascii_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+/// Follow a local `#/components/schemas/...` reference; other schemas are returned as-is.
+fn resolve_schema<'a>(
+    spec: &'a serde_json::Value,
+    schema: &'a serde_json::Value,
+) -> &'a serde_json::Value {
+    match schema["$ref"].as_str().and_then(|r| r.strip_prefix("#/")) {
+        Some(pointer) => spec
+            .pointer(&format!("/{}", pointer))
+            .unwrap_or(&serde_json::Value::Null),
+        None => schema,
+    }
+}
+
+/// An example value for `schema`: its own `example`, else one built from its type.
+fn sample_value(
+    spec: &serde_json::Value,
+    schema: &serde_json::Value,
+    depth: usize,
+) -> serde_json::Value {
+    let schema = resolve_schema(spec, schema);
+    if let Some(example) = schema.get("example") {
+        return example.clone();
+    }
+    if let Some(first) = schema["enum"].as_array().and_then(|values| values.first()) {
+        return first.clone();
+    }
+    if depth > 4 {
+        return serde_json::Value::Null;
+    }
+    match schema["type"].as_str() {
+        Some("object") => {
+            let properties = schema["properties"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default();
+            let object = properties
+                .iter()
+                .map(|(name, property)| (name.clone(), sample_value(spec, property, depth + 1)))
+                .collect();
+            serde_json::Value::Object(object)
+        }
+        Some("array") => serde_json::json!([sample_value(spec, &schema["items"], depth + 1)]),
+        Some("integer") => serde_json::json!(1),
+        Some("number") => serde_json::json!(1.5),
+        Some("boolean") => serde_json::json!(true),
+        Some("string") => match schema["format"].as_str() {
+            Some("email") => "user@example.com".into(),
+            Some("date") => "2024-01-01".into(),
+            Some("date-time") => "2024-01-01T00:00:00Z".into(),
+            Some("uuid") => "00000000-0000-0000-0000-000000000001".into(),
+            _ => "string".into(),
+        },
+        _ => serde_json::Value::Null,
+    }
+}
+
+/// The `application/json` example under `content`, or a sample built from its schema.
+fn content_example(
+    spec: &serde_json::Value,
+    content: &serde_json::Value,
+) -> Option<serde_json::Value> {
+    let media = &content["application/json"];
+    if let Some(example) = media.get("example") {
+        return Some(example.clone());
+    }
+    media
+        .get("schema")
+        .map(|schema| sample_value(spec, schema, 0))
+}
+
+/// One contract case per operation in `spec`, expecting its first documented 2xx response.
+fn contract_cases(spec: &serde_json::Value) -> Vec<ContractCase> {
+    let mut cases: Vec<ContractCase> = Vec::new();
+    let paths = spec["paths"].as_object().cloned().unwrap_or_default();
+
+    for (path, item) in &paths {
+        for method in HttpMethod::ALL {
+            let Some(operation) = item.get(method.key()) else {
+                continue;
+            };
+            let mut name = handler_name(method, path, operation);
+            if cases.iter().any(|c| c.name == name) {
+                name = format!("{}_{}", name, cases.len() + 1);
+            }
+
+            let parameters: Vec<&serde_json::Value> = item["parameters"]
+                .as_array()
+                .into_iter()
+                .chain(operation["parameters"].as_array())
+                .flatten()
+                .map(|parameter| resolve_schema(spec, parameter))
+                .collect();
+            let request_path = path_params(path).iter().fold(path.clone(), |p, param| {
+                let value = parameters
+                    .iter()
+                    .find(|candidate| candidate["in"] == "path" && candidate["name"] == *param)
+                    .and_then(|parameter| {
+                        parameter
+                            .get("example")
+                            .cloned()
+                            .or_else(|| parameter.get("schema").map(|s| sample_value(spec, s, 0)))
+                    });
+                let value = match value {
+                    Some(serde_json::Value::String(s)) => s,
+                    Some(serde_json::Value::Null) | None => "1".to_string(),
+                    Some(other) => other.to_string(),
+                };
+                p.replace(&format!("{{{}}}", param), &percent_encode(&value))
+            });
+
+            let request_body = operation
+                .get("requestBody")
+                .map(|body| resolve_schema(spec, body))
+                .and_then(|body| content_example(spec, &body["content"]));
+
+            let responses = operation["responses"].as_object();
+            let success = responses.and_then(|responses| {
+                responses
+                    .iter()
+                    .filter_map(|(code, response)| Some((code.parse::<u16>().ok()?, response)))
+                    .find(|(code, _)| (200..300).contains(code))
+            });
+            let (expected_status, response) = match success {
+                Some((code, response)) => (code, resolve_schema(spec, response)),
+                None => (200, &serde_json::Value::Null),
+            };
+            let expected_fields = response["content"]["application/json"]
+                .get("schema")
+                .map(|schema| resolve_schema(spec, schema))
+                .and_then(|schema| schema["required"].as_array())
+                .map(|required| {
+                    required
+                        .iter()
+                        .filter_map(|f| f.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            cases.push(ContractCase {
+                name,
+                method,
+                path: path.clone(),
+                request_path,
+                request_body,
+                expected_status,
+                expected_fields,
+            });
+        }
+    }
+    cases
+}
+
+/// `value` with every byte outside the RFC 3986 unreserved set percent-encoded, for use as
+/// a single path segment.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `value` as a Python literal.
+fn python_literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => "None".to_string(),
+        serde_json::Value::Bool(b) => if *b { "True" } else { "False" }.to_string(),
+        serde_json::Value::Number(_) | serde_json::Value::String(_) => value.to_string(),
+        serde_json::Value::Array(items) => {
+            format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(python_literal)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        }
+        serde_json::Value::Object(fields) => format!(
+            "{{{}}}",
+            fields
+                .iter()
+                .map(|(k, v)| format!(
+                    "{}: {}",
+                    serde_json::Value::from(k.as_str()),
+                    python_literal(v)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Render `cases` as a test file for `framework`. Tests target `BASE_URL` from the environment.
+fn render_contract_tests(
+    title: &str,
+    cases: &[ContractCase],
+    framework: ContractFramework,
+) -> String {
+    let mut code = match framework {
+        ContractFramework::Pytest => format!(
+            "\"\"\"Contract tests for {} generated from its OpenAPI spec.\"\"\"\n\
+             import os\n\nimport requests\n\n\
+             BASE_URL = os.environ.get(\"BASE_URL\", \"http://localhost:8080\")\n",
+            title
+        ),
+        ContractFramework::Jest => format!(
+            "// Contract tests for {} generated from its OpenAPI spec.\n\
+             const BASE_URL = process.env.BASE_URL || \"http://localhost:8080\";\n",
+            title
+        ),
+    };
+
+    for case in cases {
+        let operation = format!("{} {}", case.method, case.path);
+        let fields = serde_json::to_string(&case.expected_fields).unwrap();
+        match framework {
+            ContractFramework::Pytest => {
+                let body = case
+                    .request_body
+                    .as_ref()
+                    .map(|b| format!(", json={}", python_literal(b)))
+                    .unwrap_or_default();
+                code.push_str(&format!(
+                    "\n\ndef test_{}():\n    \"\"\"{}\"\"\"\n    \
+                     response = requests.request(\"{}\", f\"{{BASE_URL}}{}\"{})\n    \
+                     assert response.status_code == {}\n",
+                    case.name,
+                    operation,
+                    case.method,
+                    case.request_path.replace('{', "{{").replace('}', "}}"),
+                    body,
+                    case.expected_status
+                ));
+                if !case.expected_fields.is_empty() {
+                    code.push_str(&format!(
+                        "    body = response.json()\n    for field in {}:\n        assert field in body\n",
+                        fields
+                    ));
+                }
+            }
+            ContractFramework::Jest => {
+                let body = case
+                    .request_body
+                    .as_ref()
+                    .map(|b| {
+                        format!(
+                            ",\n    headers: {{ \"Content-Type\": \"application/json\" }},\n    body: JSON.stringify({})",
+                            b
+                        )
+                    })
+                    .unwrap_or_default();
+                code.push_str(&format!(
+                    "\ntest(\"{}\", async () => {{\n  \
+                     const response = await fetch(`${{BASE_URL}}{}`, {{\n    method: \"{}\"{}\n  }});\n  \
+                     expect(response.status).toBe({});\n",
+                    operation, case.request_path, case.method, body, case.expected_status
+                ));
+                if !case.expected_fields.is_empty() {
+                    code.push_str(&format!(
+                        "  const body = await response.json();\n  for (const field of {}) {{\n    \
+                         expect(body).toHaveProperty(field);\n  }}\n",
+                        fields
+                    ));
+                }
+                code.push_str("});\n");
+            }
+        }
+    }
+    code
+}
+
+async fn contract_tests(req: web::Json<ContractTestRequest>) -> impl Responder {
+    let result = ContractFramework::parse(&req.framework)
+        .and_then(|framework| parse_spec(&req.spec).map(|spec| (framework, spec)));
+    match result {
+        Ok((framework, spec)) => {
+            let cases = contract_cases(&spec);
+            let code = render_contract_tests(
+                spec["info"]["title"].as_str().unwrap_or("api"),
+                &cases,
+                framework,
+            );
+            HttpResponse::Ok().json(ContractTestResponse {
+                framework: framework.name().to_string(),
+                cases,
+                code,
+            })
+        }
+        Err(error) => HttpResponse::BadRequest().json(serde_json::json!({ "error": error })),
+    }
+}
+
+async fn scaffold_design(req: web::Json<ScaffoldRequest>) -> impl Responder {
+    let result = ScaffoldLanguage::parse(&req.language)
+        .and_then(|language| parse_spec(&req.spec).map(|spec| (language, spec)));
+    match result {
+        Ok((language, spec)) => {
+            let (handlers, code) = scaffold(&spec, language);
+            HttpResponse::Ok().json(ScaffoldResponse {
+                language: language.name().to_string(),
+                framework: language.framework().to_string(),
+                handlers,
+                code,
+            })
+        }
+        Err(error) => HttpResponse::BadRequest().json(serde_json::json!({ "error": error })),
+    }
+}
+
+async fn update_design(req: web::Json<SpecUpdateRequest>) -> impl Responder {
+    match update_spec(&req.spec, &req.change_description) {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(error) => HttpResponse::BadRequest().json(serde_json::json!({ "error": error })),
+    }
+}
+
+async fn validate_spec(req: web::Json<ValidateRequest>) -> impl Responder {
+    HttpResponse::Ok().json(validate_document(&req.spec))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let app_state = web::Data::new(AppState {
@@ -99,10 +2089,712 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
+            .app_data(json_config())
             .route("/health", web::get().to(health))
             .route("/api/v1/design", web::post().to(design_api))
+            .route("/api/v1/design/update", web::post().to(update_design))
+            .route("/api/v1/design/scaffold", web::post().to(scaffold_design))
+            .route(
+                "/api/v1/design/contract-tests",
+                web::post().to(contract_tests),
+            )
+            .route("/api/v1/validate", web::post().to(validate_spec))
     })
     .bind(("0.0.0.0", 8106))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as actix_test;
+
+    const SPEC: &str = r#"{
+  "openapi": "3.0.0",
+  "info": { "title": "users", "version": "1.0.0" },
+  "paths": {
+    "/users": { "get": { "description": "List users" } }
+  }
+}"#;
+
+    #[actix_web::test]
+    async fn update_returns_patch_that_produces_expected_spec() {
+        let app = actix_test::init_service(
+            App::new().route("/api/v1/design/update", web::post().to(update_design)),
+        )
+        .await;
+        let request = actix_test::TestRequest::post()
+            .uri("/api/v1/design/update")
+            .set_json(serde_json::json!({
+                "spec": SPEC,
+                "change_description": "add POST /users: Create a user\nadd GET /users/{id}: Fetch a user\nset version 1.1.0",
+            }))
+            .to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+
+        let patch: json_patch::Patch = serde_json::from_value(body["patch"].clone()).unwrap();
+        let mut spec: serde_json::Value = serde_json::from_str(SPEC).unwrap();
+        json_patch::patch(&mut spec, &patch).unwrap();
+
+        let expected = serde_json::json!({
+            "openapi": "3.0.0",
+            "info": { "title": "users", "version": "1.1.0" },
+            "paths": {
+                "/users": {
+                    "get": { "description": "List users" },
+                    "post": { "description": "Create a user" }
+                },
+                "/users/{id}": { "get": { "description": "Fetch a user" } }
+            }
+        });
+        assert_eq!(spec, expected);
+        assert_eq!(validate_openapi(&spec), Ok(()));
+        let updated: serde_json::Value =
+            serde_json::from_str(body["updated_spec"].as_str().unwrap()).unwrap();
+        assert_eq!(updated, expected);
+    }
+
+    #[test]
+    fn update_rejects_unknown_changes_and_missing_operations() {
+        let error = update_spec(SPEC, "make it faster").err().unwrap();
+        assert!(error.contains("unrecognized change"), "{}", error);
+        let error = update_spec(SPEC, "remove DELETE /users").err().unwrap();
+        assert!(error.contains("DELETE /users does not exist"), "{}", error);
+        let error = update_spec(r#"{"info": {}}"#, "set title x").err().unwrap();
+        assert!(error.contains("invalid spec"), "{}", error);
+    }
+
+    #[test]
+    fn removing_last_operation_removes_path() {
+        let response = update_spec(SPEC, "remove GET /users").unwrap();
+        assert_eq!(
+            serde_json::to_value(&response.patch).unwrap(),
+            serde_json::json!([{ "op": "remove", "path": "/paths/~1users" }])
+        );
+    }
+
+    const SCAFFOLD_SPEC: &str = r#"{
+  "openapi": "3.0.0",
+  "info": { "title": "users", "version": "1.0.0" },
+  "paths": {
+    "/users": {
+      "get": { "description": "List users" },
+      "post": { "description": "Create a user" }
+    },
+    "/users/{id}": { "delete": { "operationId": "deleteUser" } }
+  }
+}"#;
+
+    #[actix_web::test]
+    async fn rust_scaffold_has_an_actix_route_per_operation() {
+        let app = actix_test::init_service(
+            App::new().route("/api/v1/design/scaffold", web::post().to(scaffold_design)),
+        )
+        .await;
+        let request = actix_test::TestRequest::post()
+            .uri("/api/v1/design/scaffold")
+            .set_json(serde_json::json!({ "spec": SCAFFOLD_SPEC, "language": "rust" }))
+            .to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        let code = body["code"].as_str().unwrap();
+
+        assert_eq!(body["framework"], "actix-web");
+        for (route, handler) in [
+            (
+                r#".route("/users", web::get().to(get_users))"#,
+                "async fn get_users()",
+            ),
+            (
+                r#".route("/users", web::post().to(post_users))"#,
+                "async fn post_users()",
+            ),
+            (
+                r#".route("/users/{id}", web::delete().to(delete_user))"#,
+                "async fn delete_user(path: web::Path<String>)",
+            ),
+        ] {
+            assert!(code.contains(route), "missing {} in\n{}", route, code);
+            assert!(code.contains(handler), "missing {} in\n{}", handler, code);
+        }
+        assert_eq!(code.matches(".route(").count(), 3);
+        assert!(code.contains("/// List users"));
+    }
+
+    #[test]
+    fn python_and_javascript_scaffolds_use_their_frameworks() {
+        let spec = parse_spec(SCAFFOLD_SPEC).unwrap();
+        let (handlers, python) = scaffold(&spec, ScaffoldLanguage::Python);
+        assert_eq!(handlers.len(), 3);
+        assert!(python.contains("@app.delete(\"/users/{id}\")\nasync def delete_user(id: str):"));
+        let (_, javascript) = scaffold(&spec, ScaffoldLanguage::JavaScript);
+        assert!(javascript.contains("app.delete(\"/users/:id\", function deleteUser(req, res)"));
+    }
+
+    #[test]
+    fn scaffold_comments_every_description_line_and_keeps_names_unique() {
+        let spec = parse_spec(
+            r#"{
+  "openapi": "3.0.0",
+  "info": { "title": "things", "version": "1.0.0" },
+  "paths": {
+    "/a": { "get": { "operationId": "list", "description": "Lists things.\nSays \"\"\"hi\"\"\"", "responses": {} } },
+    "/b": { "get": { "operationId": "list_3", "responses": {} } },
+    "/c": { "get": { "operationId": "list", "responses": {} } }
+  }
+}"#,
+        )
+        .unwrap();
+        let (handlers, rust) = scaffold(&spec, ScaffoldLanguage::Rust);
+        let names: Vec<&str> = handlers.iter().map(|h| h.name.as_str()).collect();
+        assert_eq!(names, ["list", "list_3", "list_4"]);
+        assert!(rust.contains("/// Lists things.\n/// Says \"\"\"hi\"\"\"\nasync fn list()"), "{}", rust);
+        let (_, python) = scaffold(&spec, ScaffoldLanguage::Python);
+        assert!(python.contains("    \"\"\"Lists things.\n    Says \\\"\\\"\\\"hi\\\"\\\"\\\"\"\"\"\n"), "{}", python);
+        let (_, javascript) = scaffold(&spec, ScaffoldLanguage::JavaScript);
+        assert!(javascript.contains("// Lists things.\n// Says"), "{}", javascript);
+    }
+
+    #[test]
+    fn scaffold_rejects_unsupported_language() {
+        let error = ScaffoldLanguage::parse("cobol").err().unwrap();
+        assert!(error.contains("rust, python, javascript"), "{}", error);
+    }
+
+    const CONTRACT_SPEC: &str = r##"{
+  "openapi": "3.0.0",
+  "info": { "title": "users", "version": "1.0.0" },
+  "paths": {
+    "/users": {
+      "get": { "description": "List users", "responses": { "200": { "description": "ok" } } },
+      "post": {
+        "requestBody": { "content": { "application/json": { "schema": { "$ref": "#/components/schemas/NewUser" } } } },
+        "responses": {
+          "201": {
+            "description": "created",
+            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/User" } } }
+          }
+        }
+      }
+    },
+    "/users/{id}": {
+      "parameters": [{ "name": "id", "in": "path", "required": true, "example": 42 }],
+      "delete": { "operationId": "deleteUser", "responses": { "204": { "description": "gone" } } }
+    }
+  },
+  "components": {
+    "schemas": {
+      "NewUser": {
+        "type": "object",
+        "required": ["email"],
+        "properties": { "email": { "type": "string", "format": "email" }, "admin": { "type": "boolean" } }
+      },
+      "User": {
+        "type": "object",
+        "required": ["id", "email"],
+        "properties": { "id": { "type": "integer" }, "email": { "type": "string" } }
+      }
+    }
+  }
+}"##;
+
+    #[actix_web::test]
+    async fn contract_tests_cover_every_operation() {
+        let app = actix_test::init_service(App::new().route(
+            "/api/v1/design/contract-tests",
+            web::post().to(contract_tests),
+        ))
+        .await;
+        let request = actix_test::TestRequest::post()
+            .uri("/api/v1/design/contract-tests")
+            .set_json(serde_json::json!({ "spec": CONTRACT_SPEC, "framework": "pytest" }))
+            .to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+        let code = body["code"].as_str().unwrap();
+
+        assert_eq!(body["cases"].as_array().unwrap().len(), 3);
+        for (test, operation, call) in [
+            (
+                "def test_get_users():",
+                "GET /users",
+                r#"requests.request("GET", f"{BASE_URL}/users")"#,
+            ),
+            (
+                "def test_post_users():",
+                "POST /users",
+                r#"requests.request("POST", f"{BASE_URL}/users", json={"admin": True, "email": "user@example.com"})"#,
+            ),
+            (
+                "def test_delete_user():",
+                "DELETE /users/{id}",
+                r#"requests.request("DELETE", f"{BASE_URL}/users/42")"#,
+            ),
+        ] {
+            assert!(code.contains(test), "missing {} in\n{}", test, code);
+            assert!(
+                code.contains(operation),
+                "missing {} in\n{}",
+                operation,
+                code
+            );
+            assert!(code.contains(call), "missing {} in\n{}", call, code);
+        }
+        assert!(code.contains("assert response.status_code == 201"));
+        assert!(code.contains("assert response.status_code == 204"));
+        assert!(code.contains(r#"for field in ["id","email"]:"#));
+
+        let cases = contract_cases(&parse_spec(CONTRACT_SPEC).unwrap());
+        let jest = render_contract_tests("users", &cases, ContractFramework::Jest);
+        for case in &cases {
+            assert!(
+                jest.contains(&format!("test(\"{} {}\"", case.method, case.path)),
+                "{}",
+                jest
+            );
+        }
+    }
+
+    #[test]
+    fn contract_tests_reject_unknown_framework() {
+        let error = ContractFramework::parse("rspec").err().unwrap();
+        assert!(error.contains("pytest, jest"), "{}", error);
+    }
+
+    #[actix_web::test]
+    async fn http_methods_parse_case_insensitively() {
+        for method in ["get", "GET", "Get"] {
+            let endpoint: EndpointSpec = serde_json::from_value(serde_json::json!({
+                "path": "/users", "method": method, "description": "List users"
+            }))
+            .unwrap();
+            assert_eq!(endpoint.method, HttpMethod::Get);
+        }
+        assert_eq!("trace".parse::<HttpMethod>(), Ok(HttpMethod::Trace));
+        assert_eq!(HttpMethod::Trace.to_string(), "TRACE");
+        let error = "FOO".parse::<HttpMethod>().unwrap_err();
+        assert!(error.contains("unknown HTTP method 'FOO'"), "{}", error);
+
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    designs_count: Mutex::new(0),
+                }))
+                .route("/api/v1/design", web::post().to(design_api)),
+        )
+        .await;
+        let design = |endpoints: serde_json::Value| {
+            actix_test::TestRequest::post()
+                .uri("/api/v1/design")
+                .set_json(serde_json::json!({
+                    "service_name": "users", "auth_type": "oauth2", "endpoints": endpoints
+                }))
+                .to_request()
+        };
+
+        let rejected = design(serde_json::json!([
+            { "path": "/users", "method": "FOO", "description": "?" }
+        ]));
+        let response = actix_test::call_service(&app, rejected).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+        let duplicate = design(serde_json::json!([
+            { "path": "/users", "method": "get", "description": "List users" },
+            { "path": "/users", "method": "GET", "description": "List users again" }
+        ]));
+        let response = actix_test::call_service(&app, duplicate).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = actix_test::read_body_json(response).await;
+        assert_eq!(body["error"], "duplicate endpoint GET /users");
+
+        let valid = design(serde_json::json!([
+            { "path": "/users", "method": "get", "description": "List users" },
+            { "path": "/users", "method": "Post", "description": "Create a user" }
+        ]));
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, valid).await;
+        let spec = parse_spec(body["openapi_spec"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            spec["paths"]["/users"]["post"]["description"],
+            "Create a user"
+        );
+        assert_eq!(spec["paths"]["/users"]["get"]["description"], "List users");
+    }
+
+    #[actix_web::test]
+    async fn design_returns_openapi_and_postman_artifacts() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(web::Data::new(AppState {
+                    designs_count: Mutex::new(0),
+                }))
+                .route("/api/v1/design", web::post().to(design_api)),
+        )
+        .await;
+        let design = |formats: serde_json::Value| {
+            actix_test::TestRequest::post()
+                .uri("/api/v1/design")
+                .set_json(serde_json::json!({
+                    "service_name": "users",
+                    "auth_type": "bearer",
+                    "endpoints": [
+                        { "path": "/users", "method": "GET", "description": "List users" },
+                        { "path": "/users/{id}", "method": "DELETE", "description": "Delete a user" }
+                    ],
+                    "output_formats": formats
+                }))
+                .to_request()
+        };
+
+        let body: serde_json::Value = actix_test::call_and_read_body_json(
+            &app,
+            design(serde_json::json!(["openapi", "Postman"])),
+        )
+        .await;
+        let artifacts = body["artifacts"].as_object().unwrap();
+        assert_eq!(artifacts.keys().collect::<Vec<_>>(), ["openapi", "postman"]);
+
+        let openapi = parse_spec(artifacts["openapi"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            openapi["paths"]["/users"]["get"]["description"],
+            "List users"
+        );
+
+        let postman: serde_json::Value =
+            serde_json::from_str(artifacts["postman"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            postman["info"]["schema"],
+            "https://schema.getpostman.com/json/collection/v2.1.0/collection.json"
+        );
+        assert_eq!(postman["auth"]["type"], "bearer");
+        let requests: Vec<(&str, &str)> = postman["item"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| {
+                let request = &item["request"];
+                (
+                    request["method"].as_str().unwrap(),
+                    request["url"]["raw"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            requests,
+            [
+                ("GET", "{{baseUrl}}/users"),
+                ("DELETE", "{{baseUrl}}/users/:id")
+            ]
+        );
+        assert_eq!(
+            postman["item"][1]["request"]["url"]["variable"][0]["key"],
+            "id"
+        );
+
+        let response =
+            actix_test::call_service(&app, design(serde_json::json!(["openapi", "raml"]))).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = actix_test::read_body_json(response).await;
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("unknown output format 'raml'"));
+    }
+
+    #[test]
+    fn graphql_schema_splits_reads_and_writes() {
+        let mut spec = parse_spec(SPEC).unwrap();
+        spec["paths"]["/users/{id}"] =
+            serde_json::json!({ "put": { "description": "Replace a user" } });
+        let schema = graphql_schema(&spec);
+        assert!(
+            schema.contains("type Query {\n  \"\"\"List users\"\"\"\n  getUsers: JSON\n}"),
+            "{}",
+            schema
+        );
+        assert!(
+            schema.contains("  putUsersId(id: ID!, input: JSON): JSON\n"),
+            "{}",
+            schema
+        );
+
+        spec["paths"]["/accounts/{id}"] = serde_json::json!({
+            "put": { "operationId": "putUsersId", "description": "Replace \"a\" user\nor \"\"\"create\"\"\" one" }
+        });
+        let schema = graphql_schema(&spec);
+        assert!(
+            schema.contains(
+                "  \"\"\"Replace \"a\" user\n  or \\\"\"\"create\\\"\"\" one\"\"\"\n  putUsersId(id: ID!, input: JSON): JSON\n"
+            ),
+            "{}",
+            schema
+        );
+        assert!(
+            schema.contains("  putUsersId2(id: ID!, input: JSON): JSON\n"),
+            "{}",
+            schema
+        );
+    }
+
+    #[actix_web::test]
+    async fn validate_reports_errors_in_an_invalid_yaml_spec() {
+        let app = actix_test::init_service(
+            App::new().route("/api/v1/validate", web::post().to(validate_spec)),
+        )
+        .await;
+        let spec = r##"
+openapi: "3.1.0"
+info:
+  title: users
+paths:
+  /users/{id}:
+    get:
+      operationId: getUser
+      parameters:
+        - name: id
+          in: path
+      responses:
+        "200":
+          description: The user
+          content:
+            application/json:
+              schema:
+                $ref: "#/components/schemas/User"
+        "600":
+          description: Out of range
+  /users/:
+    get:
+      operationId: getUser  # duplicated on purpose
+      summary: List users
+      responses:
+        "200": { description: The users }
+components:
+  schemas:
+    Account:
+      type: object
+      properties:
+        email: { type: string, nullable: true }
+"##;
+        let request = actix_test::TestRequest::post()
+            .uri("/api/v1/validate")
+            .set_json(serde_json::json!({ "spec": spec }))
+            .to_request();
+        let body: serde_json::Value = actix_test::call_and_read_body_json(&app, request).await;
+
+        assert_eq!(body["valid"], false);
+        assert_eq!(body["format"], "yaml");
+        assert_eq!(body["openapi_version"], "3.1.0");
+        let errors: Vec<(&str, &str)> = body["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| (e["path"].as_str().unwrap(), e["message"].as_str().unwrap()))
+            .collect();
+        let expected = [
+            ("/info/version", "missing required string"),
+            (
+                "/paths/~1users~1{id}/get/parameters/0/required",
+                "path parameter 'id' must be required",
+            ),
+            (
+                "/paths/~1users~1{id}/get/responses/600",
+                "'600' is not a status code, a range like 4XX or default",
+            ),
+            (
+                "/paths/~1users~1{id}/get/operationId",
+                "operationId 'getUser' is also used by /paths/~1users~1/get",
+            ),
+            (
+                "/components/schemas/Account/properties/email/nullable",
+                "nullable was removed in 3.1; add \"null\" to type instead",
+            ),
+            (
+                "/paths/~1users~1{id}/get/responses/200/content/application~1json/schema/$ref",
+                "'#/components/schemas/User' does not resolve",
+            ),
+        ];
+        for error in expected {
+            assert!(
+                errors.contains(&error),
+                "missing {:?} in {:?}",
+                error,
+                errors
+            );
+        }
+        assert_eq!(errors.len(), expected.len(), "{:?}", errors);
+
+        let rules: Vec<(&str, &str)> = body["lint"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| (f["path"].as_str().unwrap(), f["rule"].as_str().unwrap()))
+            .collect();
+        assert!(
+            rules.contains(&("/paths/~1users~1", "path-trailing-slash")),
+            "{:?}",
+            rules
+        );
+        assert!(
+            rules.contains(&("/paths/~1users~1{id}/get", "operation-description")),
+            "{:?}",
+            rules
+        );
+        assert!(
+            rules.contains(&("/paths/~1users~1/get/responses", "error-responses")),
+            "{:?}",
+            rules
+        );
+    }
+
+    #[test]
+    fn validate_checks_json_against_openapi_3_0() {
+        let report = validate_document(SPEC);
+        assert_eq!(report.format, "json");
+        assert_eq!(
+            report.errors,
+            vec![SpecError {
+                path: "/paths/~1users/get/responses".to_string(),
+                message: "missing required object".to_string(),
+            }]
+        );
+
+        let mut spec = parse_spec(SPEC).unwrap();
+        spec["paths"]["/users"]["get"]["responses"] = serde_json::json!({
+            "200": {
+                "description": "The users",
+                "content": { "application/json": { "schema": { "type": ["array", "null"] } } }
+            },
+            "default": { "description": "An error" }
+        });
+        let report = validate_document(&spec.to_string());
+        assert_eq!(
+            report.errors,
+            vec![SpecError {
+                path: "/paths/~1users/get/responses/200/content/application~1json/schema/type"
+                    .to_string(),
+                message: "must be a single type name in 3.0".to_string(),
+            }]
+        );
+        assert!(report
+            .lint
+            .iter()
+            .all(|finding| finding.rule == "operation-id"));
+
+        let report = validate_document("{ \"openapi\": ");
+        assert!(!report.valid);
+        assert_eq!(report.errors[0].path, "");
+        assert!(report.errors[0].message.starts_with("not valid JSON"));
+    }
+
+    #[test]
+    fn parse_yaml_reads_the_block_subset() {
+        let yaml = "\
+# leading comment
+---
+name: 'it''s'
+tags: [a, \"b c\", 3]
+nested:
+  - id: 1
+    ok: true
+  - plain text # trailing
+  -
+    deep: ~
+text: |
+  line one
+    indented
+folded: >-
+  one
+  two
+description: |
+  Use the # sign
+
+  second paragraph
+
+paragraphs: >
+  first
+  line
+
+  second
+url: http://example.com/a#b
+empty:
+";
+        assert_eq!(
+            parse_yaml(yaml).unwrap(),
+            serde_json::json!({
+                "name": "it's",
+                "tags": ["a", "b c", 3],
+                "nested": [{ "id": 1, "ok": true }, "plain text", { "deep": null }],
+                "text": "line one\n  indented\n",
+                "folded": "one two",
+                "description": "Use the # sign\n\nsecond paragraph\n",
+                "paragraphs": "first line\nsecond\n",
+                "url": "http://example.com/a#b",
+                "empty": null
+            })
+        );
+        assert!(parse_yaml("a: &anchor 1").unwrap_err().contains("anchors"));
+        assert!(parse_yaml("a: 1\n   b: 2").unwrap_err().contains("line 2"));
+    }
+
+    #[actix_web::test]
+    async fn malformed_json_bodies_get_a_structured_error() {
+        let app = actix_test::init_service(
+            App::new()
+                .app_data(json_config())
+                .route("/api/v1/validate", web::post().to(validate_spec)),
+        )
+        .await;
+        let post = |body: &str| {
+            actix_test::TestRequest::post()
+                .uri("/api/v1/validate")
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let response = actix_test::call_service(&app, post("{\"spec\": \"openapi\",,}")).await;
+        assert_eq!(response.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let body: ErrorBody = actix_test::read_body_json(response).await;
+        assert_eq!(body.code, "invalid_json");
+        assert_eq!((body.line, body.column), (Some(1), Some(20)));
+        assert!(
+            body.error.starts_with("request body is not valid"),
+            "{}",
+            body.error
+        );
+
+        let response = actix_test::call_service(&app, post("{\"spec\": 3}")).await;
+        let body: ErrorBody = actix_test::read_body_json(response).await;
+        assert_eq!(body.code, "invalid_request");
+        assert!(body.error.contains("invalid type"), "{}", body.error);
+    }
+    #[test]
+    fn contract_tests_encode_path_values() {
+        let spec = parse_spec(
+            r#"{
+  "openapi": "3.0.0",
+  "info": {"title": "files", "version": "1"},
+  "paths": {
+    "/files/{name}": {
+      "get": {
+        "operationId": "getFile",
+        "parameters": [
+          {"name": "name", "in": "path", "required": true, "example": "a b/{c}"}
+        ],
+        "responses": {"200": {"description": "ok"}}
+      }
+    }
+  }
+}"#,
+        )
+        .unwrap();
+        let mut cases = contract_cases(&spec);
+        assert_eq!(cases[0].request_path, "/files/a%20b%2F%7Bc%7D");
+
+        cases[0].request_path = "/files/{raw}".to_string();
+        let pytest = render_contract_tests("files", &cases, ContractFramework::Pytest);
+        assert!(
+            pytest.contains(r#"f"{BASE_URL}/files/{{raw}}""#),
+            "{}",
+            pytest
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Load test for the code generator's hot path (prompt building, response parsing, caching,
+//! history and metrics).
+//!
+//! Starts the release binary on a spare port, drives concurrent `POST /api/v1/generate`
+//! requests at it and reports throughput and p50/p95/p99 latency. Exits non-zero when a
+//! threshold is missed, so it can gate CI.
+//!
+//! The binary still needs Redis; point it at a scratch database since the run fills the
+//! cache and history:
+//!
+//! ```text
+//! REDIS_URL=redis://localhost:6379/15 cargo bench --bench load
+//! ```
+//!
+//! Tuning (environment variables):
+//! - `BENCH_REQUESTS` (default 10000): total requests sent
+//! - `BENCH_CONCURRENCY` (default 1000): requests in flight at once
+//! - `BENCH_PORT` (default 18082): port the service is started on
+//! - `BENCH_MAX_P99_MS` (default 500): fail if p99 latency exceeds this
+//! - `BENCH_MIN_RPS` (default 1000): fail if throughput drops below this
+
+use futures_util::StreamExt;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Kills the service when the bench exits, including on panic.
+struct Service(Child);
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Value at quantile `q` of an ascending slice.
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    let index = ((sorted.len() as f64 - 1.0) * q).round() as usize;
+    sorted[index]
+}
+
+#[actix_web::main]
+async fn main() {
+    let requests: usize = env_or("BENCH_REQUESTS", 10_000);
+    let concurrency: usize = env_or("BENCH_CONCURRENCY", 1_000);
+    let port: u16 = env_or("BENCH_PORT", 18_082);
+    let max_p99_ms: u64 = env_or("BENCH_MAX_P99_MS", 500);
+    let min_rps: f64 = env_or("BENCH_MIN_RPS", 1_000.0);
+
+    let service = Service(
+        Command::new(env!("CARGO_BIN_EXE_code-generator"))
+            .env("PORT", port.to_string())
+            .env("RUST_LOG", "warn")
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start code-generator"),
+    );
+
+    let base = format!("http://127.0.0.1:{}", port);
+    let client = awc::Client::builder()
+        .connector(awc::Connector::new().limit(concurrency))
+        .timeout(Duration::from_secs(30))
+        .finish();
+
+    // Wait for the service to accept connections
+    let ready = Instant::now();
+    loop {
+        let health = client.get(format!("{}/health", base)).send().await;
+        if health.is_ok_and(|response| response.status().is_success()) {
+            break;
+        }
+        assert!(ready.elapsed() < Duration::from_secs(15), "service did not become healthy");
+        actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Descriptions are unique per request so every call misses the cache and exercises the
+    // full generation path.
+    let run = Instant::now();
+    let results: Vec<Result<Duration, String>> = futures_util::stream::iter(0..requests)
+        .map(|i| {
+            let client = &client;
+            let url = format!("{}/api/v1/generate", base);
+            async move {
+                let body = serde_json::json!({
+                    "request_id": format!("bench_{}_{}", std::process::id(), i),
+                    "language": "python",
+                    "generation_type": "module",
+                    "description": format!("bench module {}", i),
+                });
+                let start = Instant::now();
+                let mut response = client
+                    .post(url)
+                    .insert_header(("X-Request-Id", format!("bench-{}", i)))
+                    .insert_header(("X-API-Key", "bench"))
+                    .send_json(&body)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                response.body().await.map_err(|e| e.to_string())?;
+                if !response.status().is_success() {
+                    return Err(format!("status {}", response.status()));
+                }
+                Ok(start.elapsed())
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    let elapsed = run.elapsed();
+    drop(service);
+
+    let mut latencies: Vec<Duration> = results.iter().filter_map(|r| r.as_ref().ok().copied()).collect();
+    let failures = results.len() - latencies.len();
+    assert!(!latencies.is_empty(), "every request failed: {:?}", results.first());
+    latencies.sort();
+
+    let rps = latencies.len() as f64 / elapsed.as_secs_f64();
+    let (p50, p95, p99) = (
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.95),
+        percentile(&latencies, 0.99),
+    );
+
+    println!("requests:    {} ({} failed)", requests, failures);
+    println!("concurrency: {}", concurrency);
+    println!("elapsed:     {:.2?}", elapsed);
+    println!("throughput:  {:.0} req/s", rps);
+    println!("latency:     p50 {:.2?}  p95 {:.2?}  p99 {:.2?}", p50, p95, p99);
+
+    let mut regressions = Vec::new();
+    if failures > 0 {
+        regressions.push(format!("{} requests failed", failures));
+    }
+    if p99 > Duration::from_millis(max_p99_ms) {
+        regressions.push(format!("p99 {:.2?} exceeds {} ms", p99, max_p99_ms));
+    }
+    if rps < min_rps {
+        regressions.push(format!("throughput {:.0} req/s is below {:.0}", rps, min_rps));
+    }
+    if !regressions.is_empty() {
+        eprintln!("FAILED: {}", regressions.join("; "));
+        std::process::exit(1);
+    }
+}